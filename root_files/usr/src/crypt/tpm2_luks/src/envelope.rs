@@ -0,0 +1,130 @@
+use std::fs;
+use std::path::Path;
+
+use aes_gcm::{
+    Aes256Gcm,
+    Nonce,
+    aead::{Aead, KeyInit},
+};
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD as BASE64URL};
+use serde_json::json;
+use zeroize::Zeroizing;
+
+use crate::tpm;
+
+/// Envelope format version written into every `encrypt` output.
+const ENVELOPE_VERSION: u64 = 1;
+/// AES-256-GCM nonce length in bytes.
+const NONCE_LEN: usize = 12;
+
+type BoxError = Box<dyn std::error::Error>;
+
+/// Encrypts `infile` to a self-describing TPM-sealed envelope at `outfile`.
+///
+/// A random data-encryption key (DEK) protects the contents with AES-256-GCM;
+/// the DEK itself is sealed into the TPM (optionally bound to `pcrs`) and the
+/// sealed blobs travel inside the envelope, so the file is decryptable on the
+/// same TPM without any LUKS header. Mirrors how clevis-style TPM pins wrap an
+/// arbitrary payload.
+pub fn encrypt_file(infile: &str, outfile: &str, pcrs: &[u8]) -> Result<(), BoxError> {
+    let plaintext = Zeroizing::new(fs::read(Path::new(infile))?);
+
+    let (mut ctx, primary_handle) = tpm::create_primary()?;
+
+    let dek = Zeroizing::new(tpm::tpm_random_bytes(&mut ctx, 32)?);
+    let nonce_bytes = tpm::tpm_random_bytes(&mut ctx, NONCE_LEN)?;
+
+    let cipher = Aes256Gcm::new_from_slice(&dek).map_err(|e| e.to_string())?;
+    let nonce = Nonce::from_slice(&nonce_bytes);
+    let ciphertext = cipher
+        .encrypt(nonce, plaintext.as_slice())
+        .map_err(|e| e.to_string())?;
+
+    // Seal the DEK (no PIN: protection comes from the TPM and optional PCRs).
+    let pcr_selection = match pcrs.is_empty() {
+        true => None,
+        false => Some(tpm::pcr_selection_list(pcrs)?),
+    };
+    let (sealed_pub, sealed_priv) =
+        tpm::seal_secret(&mut ctx, primary_handle, &dek, &[], pcr_selection.as_ref())?;
+
+    use tss_esapi::traits::Marshall;
+    let envelope = json!({
+        "v":     ENVELOPE_VERSION,
+        "pub":   BASE64URL.encode(sealed_pub.marshall()?),
+        "priv":  BASE64URL.encode(sealed_priv.as_ref()),
+        "pcrs":  BASE64URL.encode(pcrs),
+        "nonce": BASE64URL.encode(&nonce_bytes),
+        "ct":    BASE64URL.encode(&ciphertext),
+    });
+    fs::write(Path::new(outfile), envelope.to_string())?;
+
+    println!("[+] Sealed envelope written to {}", outfile);
+    Ok(())
+}
+
+/// Reverses [`encrypt_file`]: parses the envelope at `infile`, reloads and
+/// unseals the DEK from the TPM, and writes the recovered plaintext to `outfile`.
+pub fn decrypt_file(infile: &str, outfile: &str) -> Result<(), BoxError> {
+    let envelope: serde_json::Value = serde_json::from_slice(&fs::read(Path::new(infile))?)?;
+
+    let version = envelope["v"].as_u64().ok_or("missing envelope version")?;
+    if version != ENVELOPE_VERSION {
+        return Err(format!("unsupported envelope version: {}", version).into());
+    }
+
+    let field = |name: &str| -> Result<Vec<u8>, BoxError> {
+        let encoded = envelope[name]
+            .as_str()
+            .ok_or_else(|| format!("missing envelope field: {}", name))?;
+        BASE64URL.decode(encoded).map_err(|e| e.into())
+    };
+
+    let pub_bytes = field("pub")?;
+    let priv_bytes = field("priv")?;
+    let pcrs = field("pcrs")?;
+    let nonce_bytes = field("nonce")?;
+    let ciphertext = field("ct")?;
+
+    // A tampered or truncated envelope must surface as an error, never a panic:
+    // validate the lengths that would otherwise abort inside `Nonce::from_slice`
+    // or the TPM unmarshalling below.
+    if nonce_bytes.len() != NONCE_LEN {
+        return Err(format!(
+            "invalid envelope nonce length: expected {} bytes, got {}",
+            NONCE_LEN,
+            nonce_bytes.len()
+        )
+        .into());
+    }
+    if pub_bytes.is_empty() || priv_bytes.is_empty() {
+        return Err("envelope is missing sealed-object material".into());
+    }
+
+    let pcr_selection = match pcrs.is_empty() {
+        true => None,
+        false => Some(tpm::pcr_selection_list(&pcrs)?),
+    };
+
+    let (mut ctx, primary_handle) = tpm::create_primary()?;
+    let dek = tpm::unseal_secret(
+        &mut ctx,
+        primary_handle,
+        &pub_bytes,
+        &priv_bytes,
+        &[],
+        pcr_selection.as_ref(),
+    )?;
+
+    let cipher = Aes256Gcm::new_from_slice(&dek).map_err(|e| e.to_string())?;
+    let nonce = Nonce::from_slice(&nonce_bytes);
+    let plaintext = Zeroizing::new(
+        cipher
+            .decrypt(nonce, ciphertext.as_slice())
+            .map_err(|e| e.to_string())?,
+    );
+    fs::write(Path::new(outfile), plaintext.as_slice())?;
+
+    println!("[+] Decrypted plaintext written to {}", outfile);
+    Ok(())
+}
@@ -47,21 +47,70 @@ pub fn luks_open(dev: &Path, final_key: &[u8], name: &str) -> Result<(), Libcryp
     Ok(())
 }
 
-/// Writes salt, sealed-object public blob, and sealed-object private blob as
-/// JSON tokens inside the LUKS2 header (slots 1, 2, 3).
+/// Returns the keyslot that `key` unlocks, without mapping the device.
+/// Used by `rotate` to locate the old passphrase slot before destroying it.
+pub fn luks_keyslot_for(dev: &Path, key: &[u8]) -> Result<c_uint, LibcryptErr> {
+    let mut device = CryptInit::init(dev)?;
+    device.context_handle().load::<()>(Some(EncryptionFormat::Luks2), None)?;
+
+    device.activate_handle().activate_by_passphrase(
+        None,
+        None,
+        key,
+        CryptActivate::empty(),
+    )
+}
+
+/// Adds `new_key` as a fresh LUKS2 keyslot, authorised by the existing
+/// `old_key`, and returns the new keyslot number. The volume master key is
+/// untouched, so no bulk re-encryption is needed.
+pub fn luks_add_keyslot(
+    dev: &Path,
+    old_key: &[u8],
+    new_key: &[u8],
+) -> Result<c_uint, LibcryptErr> {
+    let mut device = CryptInit::init(dev)?;
+    device.context_handle().load::<()>(Some(EncryptionFormat::Luks2), None)?;
+
+    device.keyslot_handle().add_by_passphrase(None, old_key, new_key)
+}
+
+/// Destroys the LUKS2 keyslot `keyslot` (e.g. the superseded passphrase slot).
+pub fn luks_destroy_keyslot(dev: &Path, keyslot: c_uint) -> Result<(), LibcryptErr> {
+    let mut device = CryptInit::init(dev)?;
+    device.context_handle().load::<()>(Some(EncryptionFormat::Luks2), None)?;
+
+    device.keyslot_handle().destroy(keyslot)
+}
+
+/// Writes salt, sealed-object public blob, sealed-object private blob, the PCR
+/// selection, and the KDF parameters as JSON tokens inside the LUKS2 header
+/// (slots 1, 2, 3, 4, 5).
+///
+/// `pcrs` holds the raw PCR indices the object is sealed against (empty when the
+/// volume is PIN-only); unlock replays exactly these indices. `kdf` holds the
+/// serialized KDF parameters so unlock re-derives the key with the original cost.
+/// `mac` is an HMAC over the other token payloads that unlock verifies before
+/// trusting any of them (see slot 6).
 pub fn luks2_store_tpm_tokens(
     dev: &Path,
     salt: &[u8],
     pub_bytes: &[u8],
     priv_bytes: &[u8],
+    pcrs: &[u8],
+    kdf: &[u8],
+    mac: &[u8],
 ) -> Result<(), LibcryptErr> {
     let mut device = CryptInit::init(dev)?;
     device.context_handle().load::<()>(Some(EncryptionFormat::Luks2), None)?;
 
-    let tokens: [(u32, &str, &[u8]); 3] = [
+    let tokens: [(u32, &str, &[u8]); 6] = [
         (1, "user.salt",     salt),
         (2, "user.obj_pub",  pub_bytes),
         (3, "user.obj_priv", priv_bytes),
+        (4, "user.pcrs",     pcrs),
+        (5, "user.kdf",      kdf),
+        (6, "user.mac",      mac),
     ];
 
     for (id, token_type, data) in tokens {
@@ -76,11 +125,12 @@ pub fn luks2_store_tpm_tokens(
     Ok(())
 }
 
-/// Reads salt, sealed-object public blob, and sealed-object private blob from
-/// the LUKS2 header tokens (slots 1, 2, 3).
+/// Reads salt, sealed-object public blob, sealed-object private blob, the PCR
+/// selection, the KDF parameters, and the integrity tag from the LUKS2 header
+/// tokens (slots 1, 2, 3, 4, 5, 6).
 pub fn luks2_load_tpm_tokens(
     dev: &Path,
-) -> Result<(Vec<u8>, Vec<u8>, Vec<u8>), LibcryptErr> {
+) -> Result<(Vec<u8>, Vec<u8>, Vec<u8>, Vec<u8>, Vec<u8>, Vec<u8>), LibcryptErr> {
     let mut device = CryptInit::init(dev)?;
     device.context_handle().load::<()>(Some(EncryptionFormat::Luks2), None)?;
 
@@ -89,8 +139,11 @@ pub fn luks2_load_tpm_tokens(
     let salt       = read_token(&mut token, 1)?;
     let pub_bytes  = read_token(&mut token, 2)?;
     let priv_bytes = read_token(&mut token, 3)?;
+    let pcrs       = read_token(&mut token, 4)?;
+    let kdf        = read_token(&mut token, 5)?;
+    let mac        = read_token(&mut token, 6)?;
 
-    Ok((salt, pub_bytes, priv_bytes))
+    Ok((salt, pub_bytes, priv_bytes, pcrs, kdf, mac))
 }
 
 // ---------------------------------------------------------------------------
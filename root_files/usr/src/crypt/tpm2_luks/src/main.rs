@@ -1,40 +1,154 @@
+mod envelope;
 mod luks;
 mod tpm;
 
-use argon2::Argon2;
+use argon2::{Algorithm, Argon2, Params, Version};
 use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
 use hmac::{Hmac, Mac};
+use serde_json::json;
 use sha2::Sha256;
 use std::{
     io::{self, Write},
     path::PathBuf,
 };
+use subtle::ConstantTimeEq;
 use tss_esapi::traits::Marshall;
+use zeroize::Zeroizing;
 
 type HmacSha256 = Hmac<Sha256>;
 
+/// Length of the Argon2 output that `derive_key` splits into three 32-byte
+/// slices: slice_a (LUKS-key HMAC), slice_b (TPM PIN), slice_c (token-integrity
+/// HMAC key).
+const KDF_OUTPUT_LEN: usize = 96;
+
+/// KDF parameters recorded alongside the sealed tokens, so a volume always
+/// reopens with the exact cost settings it was created with — and so those
+/// settings can be raised per-machine without breaking existing volumes.
+#[derive(Clone)]
+struct KdfParams {
+    /// Variant tag; only `argon2id` is emitted today, reserved so PBKDF2 or
+    /// scrypt could be added later without a header format break.
+    variant: String,
+    /// Memory cost in KiB.
+    memory_kib: u32,
+    /// Time cost (number of passes).
+    time_cost: u32,
+    /// Degree of parallelism (lanes).
+    parallelism: u32,
+}
+
+impl Default for KdfParams {
+    fn default() -> Self {
+        Self {
+            variant: "argon2id".to_string(),
+            memory_kib: Params::DEFAULT_M_COST,
+            time_cost: Params::DEFAULT_T_COST,
+            parallelism: Params::DEFAULT_P_COST,
+        }
+    }
+}
+
+impl KdfParams {
+    /// Reconstructs the exact `Argon2` instance these parameters describe.
+    fn argon2(&self) -> Result<Argon2<'static>, Box<dyn std::error::Error>> {
+        if self.variant != "argon2id" {
+            return Err(format!("unsupported KDF variant: {}", self.variant).into());
+        }
+        let params = Params::new(
+            self.memory_kib,
+            self.time_cost,
+            self.parallelism,
+            Some(KDF_OUTPUT_LEN),
+        )
+        .map_err(|e| e.to_string())?;
+        Ok(Argon2::new(Algorithm::Argon2id, Version::V0x13, params))
+    }
+
+    /// Serialises the parameters to the JSON byte payload stored in the header.
+    fn to_bytes(&self) -> Vec<u8> {
+        json!({
+            "variant":     self.variant,
+            "memory_kib":  self.memory_kib,
+            "time_cost":   self.time_cost,
+            "parallelism": self.parallelism,
+        })
+        .to_string()
+        .into_bytes()
+    }
+
+    /// Parses the parameters back from a stored header payload.
+    fn from_bytes(bytes: &[u8]) -> Result<Self, Box<dyn std::error::Error>> {
+        let value: serde_json::Value = serde_json::from_slice(bytes)?;
+        let field = |name: &str| -> Result<u32, Box<dyn std::error::Error>> {
+            value[name]
+                .as_u64()
+                .and_then(|n| u32::try_from(n).ok())
+                .ok_or_else(|| format!("missing or invalid KDF field: {}", name).into())
+        };
+        Ok(Self {
+            variant: value["variant"]
+                .as_str()
+                .ok_or("missing KDF variant")?
+                .to_string(),
+            memory_kib: field("memory_kib")?,
+            time_cost: field("time_cost")?,
+            parallelism: field("parallelism")?,
+        })
+    }
+}
+
 // ---------------------------------------------------------------------------
 // Crypto helpers
 // ---------------------------------------------------------------------------
 
-/// Derives a 64-byte key from `password` + `salt` using Argon2.
-/// The caller splits the output into two 32-byte halves:
+/// Derives a 96-byte key from `password` + `salt` using Argon2.
+/// The caller splits the output into three 32-byte slices:
 ///   - slice_a: HMAC key used to derive the final LUKS passphrase
 ///   - slice_b: TPM auth (PIN) that protects the sealed object
-fn derive_key(password: &str, salt: &[u8]) -> Vec<u8> {
-    let argon2 = Argon2::default();
-    let mut output = [0u8; 64];
+///   - slice_c: independent HMAC key that authenticates the header tokens
+fn derive_key(
+    password: &str,
+    salt: &[u8],
+    kdf: &KdfParams,
+) -> Result<Zeroizing<Vec<u8>>, Box<dyn std::error::Error>> {
+    let argon2 = kdf.argon2()?;
+    let mut output = Zeroizing::new(vec![0u8; KDF_OUTPUT_LEN]);
     argon2
         .hash_password_into(password.as_bytes(), salt, &mut output)
-        .expect("Argon2 hashing failed");
-    output.to_vec()
+        .map_err(|e| e.to_string())?;
+    Ok(output)
 }
 
-/// Computes HMAC-SHA-256 of `data` under `key`.
-fn compute_hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+/// Computes HMAC-SHA-256 of `data` under `key`. The returned tag is wiped on
+/// drop because it doubles as the LUKS passphrase.
+fn compute_hmac(key: &[u8], data: &[u8]) -> Zeroizing<Vec<u8>> {
     let mut mac = HmacSha256::new_from_slice(key).expect("HMAC key error");
     mac.update(data);
-    mac.finalize().into_bytes().to_vec()
+    Zeroizing::new(mac.finalize().into_bytes().to_vec())
+}
+
+/// Frames the header token payloads in a fixed order so the integrity tag covers
+/// every stored field. Each part is prefixed with its length as a little-endian
+/// u32 so a boundary shift between adjacent fields (e.g. a byte moved from the
+/// salt into the sealed object) can never alias to the same MAC input. `setup`,
+/// `unlock` and `rotate` all build the MAC input through this function.
+fn token_payload(
+    salt: &[u8],
+    pub_bytes: &[u8],
+    priv_bytes: &[u8],
+    pcrs: &[u8],
+    kdf: &[u8],
+) -> Vec<u8> {
+    let parts = [salt, pub_bytes, priv_bytes, pcrs, kdf];
+    let mut payload = Vec::with_capacity(
+        parts.iter().map(|p| 4 + p.len()).sum::<usize>(),
+    );
+    for part in parts {
+        payload.extend_from_slice(&(part.len() as u32).to_le_bytes());
+        payload.extend_from_slice(part);
+    }
+    payload
 }
 
 // ---------------------------------------------------------------------------
@@ -47,30 +161,52 @@ fn compute_hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
 ///   3. Generates a random secret and seals it into the TPM (auth = slice_b).
 ///   4. Derives the LUKS key as HMAC(slice_a, secret).
 ///   5. Formats the LUKS image and stores the TPM tokens in its header.
-fn setup(password: &str, device: &str) -> Result<(), Box<dyn std::error::Error>> {
+fn setup(
+    password: &str,
+    device: &str,
+    pcrs: &[u8],
+    kdf: &KdfParams,
+) -> Result<(), Box<dyn std::error::Error>> {
     let (mut ctx, primary_handle) = tpm::create_primary()?;
 
     let salt = tpm::tpm_random_bytes(&mut ctx, 64)?;
-    let kdf_output = derive_key(password, &salt);
-    let (slice_a, slice_b) = kdf_output.split_at(32);
+    let kdf_output = derive_key(password, &salt, kdf)?;
+    let (slice_a, rest) = kdf_output.split_at(32);
+    let (slice_b, slice_c) = rest.split_at(32);
 
-    let secret = tpm::tpm_random_bytes(&mut ctx, 64)?;
+    let secret = Zeroizing::new(tpm::tpm_random_bytes(&mut ctx, 64)?);
     let final_key = compute_hmac(slice_a, &secret);
 
     // println!("[+] Slice A:          {}", hex::encode(slice_a));
     // println!("[+] Slice B:          {}", hex::encode(slice_b));
     // println!("[+] Secret (base64):  {}", BASE64.encode(&secret));
 
+    let pcr_selection = match pcrs.is_empty() {
+        true => None,
+        false => Some(tpm::pcr_selection_list(pcrs)?),
+    };
+
     let (sealed_pub, sealed_priv) =
-        tpm::seal_secret(&mut ctx, primary_handle, &secret, slice_b)?;
+        tpm::seal_secret(&mut ctx, primary_handle, &secret, slice_b, pcr_selection.as_ref())?;
+
+    let pub_bytes = sealed_pub.marshall()?;
+    let priv_bytes = sealed_priv.as_ref();
+    let kdf_bytes = kdf.to_bytes();
+    let mac = compute_hmac(
+        slice_c,
+        &token_payload(&salt, &pub_bytes, priv_bytes, pcrs, &kdf_bytes),
+    );
 
     let device_path = PathBuf::from(device);
     luks::luks_format(&device_path, &final_key).map_err(|e| e.to_string())?;
     luks::luks2_store_tpm_tokens(
         &device_path,
         &salt,
-        &sealed_pub.marshall()?,
-        sealed_priv.as_ref(),
+        &pub_bytes,
+        priv_bytes,
+        pcrs,
+        &kdf_bytes,
+        &mac,
     )?;
 
     // println!("[+] Final key (hex):  {}", hex::encode(&final_key));
@@ -84,17 +220,47 @@ fn setup(password: &str, device: &str) -> Result<(), Box<dyn std::error::Error>>
 ///   3. Unseals the secret from the TPM (auth = slice_b).
 ///   4. Re-derives the LUKS key as HMAC(slice_a, secret).
 ///   5. Opens the LUKS device.
-fn unlock(password: &str, device: &str) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+fn unlock(
+    password: &str,
+    device: &str,
+) -> Result<Zeroizing<Vec<u8>>, Box<dyn std::error::Error>> {
     let device_path = PathBuf::from(device);
 
-    let (salt, pub_bytes, priv_bytes) = luks::luks2_load_tpm_tokens(&device_path)?;
+    let (salt, pub_bytes, priv_bytes, pcrs, kdf_bytes, mac) =
+        luks::luks2_load_tpm_tokens(&device_path)?;
 
-    let kdf_output = derive_key(password, &salt);
-    let (slice_a, slice_b) = kdf_output.split_at(32);
+    let kdf = KdfParams::from_bytes(&kdf_bytes)?;
+    let kdf_output = derive_key(password, &salt, &kdf)?;
+    let (slice_a, rest) = kdf_output.split_at(32);
+    let (slice_b, slice_c) = rest.split_at(32);
+
+    // Authenticate the header tokens before any tampered blob reaches the TPM.
+    let expected_mac = compute_hmac(
+        slice_c,
+        &token_payload(&salt, &pub_bytes, &priv_bytes, &pcrs, &kdf_bytes),
+    );
+    if expected_mac.ct_eq(&mac).unwrap_u8() != 1 {
+        return Err("LUKS2 TPM token integrity check failed".into());
+    }
+
+    let pcr_selection = match pcrs.is_empty() {
+        true => None,
+        false => Some(tpm::pcr_selection_list(&pcrs)?),
+    };
 
     let (mut ctx, primary_handle) = tpm::create_primary()?;
-    let secret =
-        tpm::unseal_secret(&mut ctx, primary_handle, &pub_bytes, &priv_bytes, slice_b)?;
+    let secret = tpm::unseal_secret(
+        &mut ctx,
+        primary_handle,
+        &pub_bytes,
+        &priv_bytes,
+        slice_b,
+        pcr_selection.as_ref(),
+    )
+    .map_err(|e| {
+        tpm::report_lockout_on_error(&mut ctx, &e);
+        e
+    })?;
 
     // println!("[+] Slice A:          {}", hex::encode(slice_a));
     // println!("[+] Slice B:          {}", hex::encode(slice_b));
@@ -109,39 +275,243 @@ fn unlock(password: &str, device: &str) -> Result<Vec<u8>, Box<dyn std::error::E
     Ok(final_key)
 }
 
+/// Rotate:
+///   1. Unseals the secret with the OLD passphrase and verifies the old
+///      `final_key` actually opens a keyslot (proof of possession).
+///   2. Generates a fresh salt and re-derives the slices from the NEW passphrase.
+///   3. Reseals the *same* secret under a new sealed object (new TPM auth) and
+///      rewrites the header tokens.
+///   4. Adds the new `HMAC(slice_a_new, secret)` passphrase to a new keyslot and
+///      destroys the old one. The LUKS master key never changes, so there is no
+///      bulk re-encryption.
+///   5. Optionally adds an independent escrow/recovery passphrase in its own
+///      keyslot, so a lost TPM or forgotten PIN is still recoverable.
+fn rotate(
+    old_password: &str,
+    new_password: &str,
+    device: &str,
+    recovery: Option<&str>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let device_path = PathBuf::from(device);
+
+    let (salt, pub_bytes, priv_bytes, pcrs, kdf_bytes, mac) =
+        luks::luks2_load_tpm_tokens(&device_path)?;
+
+    let kdf = KdfParams::from_bytes(&kdf_bytes)?;
+    let old_output = derive_key(old_password, &salt, &kdf)?;
+    let (old_slice_a, old_rest) = old_output.split_at(32);
+    let (old_slice_b, old_slice_c) = old_rest.split_at(32);
+
+    let expected_mac = compute_hmac(
+        old_slice_c,
+        &token_payload(&salt, &pub_bytes, &priv_bytes, &pcrs, &kdf_bytes),
+    );
+    if expected_mac.ct_eq(&mac).unwrap_u8() != 1 {
+        return Err("LUKS2 TPM token integrity check failed".into());
+    }
+
+    let pcr_selection = match pcrs.is_empty() {
+        true => None,
+        false => Some(tpm::pcr_selection_list(&pcrs)?),
+    };
+
+    let (mut ctx, primary_handle) = tpm::create_primary()?;
+    let secret = tpm::unseal_secret(
+        &mut ctx,
+        primary_handle,
+        &pub_bytes,
+        &priv_bytes,
+        old_slice_b,
+        pcr_selection.as_ref(),
+    )
+    .map_err(|e| {
+        tpm::report_lockout_on_error(&mut ctx, &e);
+        e
+    })?;
+
+    let old_final_key = compute_hmac(old_slice_a, &secret);
+    let old_keyslot = luks::luks_keyslot_for(&device_path, &old_final_key)?;
+
+    // Re-derive from the new passphrase against a fresh salt and reseal.
+    let new_salt = tpm::tpm_random_bytes(&mut ctx, 64)?;
+    let new_output = derive_key(new_password, &new_salt, &kdf)?;
+    let (new_slice_a, new_rest) = new_output.split_at(32);
+    let (new_slice_b, new_slice_c) = new_rest.split_at(32);
+
+    let (new_pub, new_priv) =
+        tpm::seal_secret(&mut ctx, primary_handle, &secret, new_slice_b, pcr_selection.as_ref())?;
+    let new_pub_bytes = new_pub.marshall()?;
+    let new_priv_bytes = new_priv.as_ref();
+
+    let new_mac = compute_hmac(
+        new_slice_c,
+        &token_payload(&new_salt, &new_pub_bytes, new_priv_bytes, &pcrs, &kdf_bytes),
+    );
+
+    // Install and verify the new keyslots *before* rewriting the header. Until
+    // the tokens are rewritten the volume still opens with the old passphrase,
+    // so a crash or error anywhere in here leaves it fully recoverable; only the
+    // final, atomic-enough sequence (rewrite tokens, then drop the old slot)
+    // switches the volume over to the new-salt derivation.
+    let new_final_key = compute_hmac(new_slice_a, &secret);
+    luks::luks_add_keyslot(&device_path, &old_final_key, &new_final_key)
+        .map_err(|e| e.to_string())?;
+    luks::luks_keyslot_for(&device_path, &new_final_key).map_err(|e| e.to_string())?;
+
+    if let Some(recovery) = recovery {
+        luks::luks_add_keyslot(&device_path, &new_final_key, recovery.as_bytes())
+            .map_err(|e| e.to_string())?;
+        luks::luks_keyslot_for(&device_path, recovery.as_bytes()).map_err(|e| e.to_string())?;
+        println!("[+] Recovery keyslot added.");
+    }
+
+    luks::luks2_store_tpm_tokens(
+        &device_path,
+        &new_salt,
+        &new_pub_bytes,
+        new_priv_bytes,
+        &pcrs,
+        &kdf_bytes,
+        &new_mac,
+    )?;
+
+    luks::luks_destroy_keyslot(&device_path, old_keyslot).map_err(|e| e.to_string())?;
+
+    println!("[+] Rotation complete.");
+    Ok(())
+}
+
 // ---------------------------------------------------------------------------
 // Entry point
 // ---------------------------------------------------------------------------
 
-fn read_password() -> Result<String, Box<dyn std::error::Error>> {
-    let password = rpassword::prompt_password("Enter passphrase: ")?;
-    Ok(password.trim().to_string())
+fn read_password() -> Result<Zeroizing<String>, Box<dyn std::error::Error>> {
+    let password = Zeroizing::new(rpassword::prompt_password("Enter passphrase: ")?);
+    Ok(Zeroizing::new(password.trim().to_string()))
+}
+
+/// Parses a comma-separated list of PCR indices (e.g. "0,2,4,7").
+fn parse_pcrs(spec: &str) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    spec.split(',')
+        .map(str::trim)
+        .filter(|p| !p.is_empty())
+        .map(|p| p.parse::<u8>().map_err(|e| e.into()))
+        .collect()
+}
+
+/// Pulls an optional `--flag value` pair out of `args`, returning its value.
+fn take_flag<'a>(args: &'a [String], flag: &str) -> Option<&'a str> {
+    args.iter()
+        .position(|a| a == flag)
+        .and_then(|i| args.get(i + 1))
+        .map(String::as_str)
+}
+
+/// Builds the `KdfParams` for `setup`, overriding the defaults with any
+/// `--kdf-mem` / `--kdf-time` / `--kdf-par` flags present.
+fn kdf_from_args(args: &[String]) -> Result<KdfParams, Box<dyn std::error::Error>> {
+    let mut kdf = KdfParams::default();
+    if let Some(v) = take_flag(args, "--kdf-mem") {
+        kdf.memory_kib = v.parse()?;
+    }
+    if let Some(v) = take_flag(args, "--kdf-time") {
+        kdf.time_cost = v.parse()?;
+    }
+    if let Some(v) = take_flag(args, "--kdf-par") {
+        kdf.parallelism = v.parse()?;
+    }
+    Ok(kdf)
 }
 
 fn usage(program: &str) {
-    eprintln!("Usage: {} <setup|unlock> <device>", program);
+    eprintln!("Usage: {} <setup|unlock|rotate> <device> [pcrs] [kdf flags]", program);
     eprintln!();
     eprintln!("  setup   Format the LUKS image and seal a new secret into the TPM");
     eprintln!("  unlock  Unseal the secret from the TPM and open the LUKS device");
+    eprintln!("  rotate  Change the passphrase (and reseal) without re-encrypting;");
+    eprintln!("          add --recovery to also add an escrow keyslot");
+    eprintln!("  encrypt <infile> <outfile> [pcrs]  Seal a file into a TPM envelope");
+    eprintln!("  decrypt <infile> <outfile>         Unseal a TPM envelope back to a file");
+    eprintln!("  reset-lockout                      Clear the TPM dictionary-attack counter");
+    eprintln!();
+    eprintln!("  pcrs    Optional comma-separated SHA-256 PCR indices to bind the");
+    eprintln!("          sealed object to (e.g. 0,2,4,7); stored in the header so");
+    eprintln!("          unlock replays them. Omit for a PIN-only volume.");
+    eprintln!();
+    eprintln!("  --kdf-mem <KiB> --kdf-time <passes> --kdf-par <lanes>");
+    eprintln!("          Optional Argon2 cost overrides for setup; recorded in the");
+    eprintln!("          header so unlock re-derives with the same parameters.");
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     let args: Vec<String> = std::env::args().collect();
 
-    if args.len() != 3 {
+    if args.len() < 2 {
+        usage(&args[0]);
+        std::process::exit(1);
+    }
+
+    // `reset-lockout` is the only action that does not take a device/file operand.
+    if args[1] != "reset-lockout" && args.len() < 3 {
         usage(&args[0]);
         std::process::exit(1);
     }
 
     match args[1].as_str() {
+        "reset-lockout" => {
+            tpm::reset_lockout()?;
+        }
         "setup" => {
+            let pcrs = match args.get(3) {
+                Some(spec) if !spec.starts_with("--") => parse_pcrs(spec)?,
+                _ => Vec::new(),
+            };
+            let kdf = kdf_from_args(&args)?;
             let password = read_password()?;
-            setup(&password, &args[2])?;
+            setup(&password, &args[2], &pcrs, &kdf)?;
         }
         "unlock" => {
             let password = read_password()?;
             unlock(&password, &args[2])?;
         }
+        "rotate" => {
+            let want_recovery = args.iter().any(|a| a == "--recovery");
+            eprintln!("[*] Enter the CURRENT passphrase:");
+            let old_password = read_password()?;
+            eprintln!("[*] Enter the NEW passphrase:");
+            let new_password = read_password()?;
+            let recovery = match want_recovery {
+                true => {
+                    eprintln!("[*] Enter the RECOVERY passphrase:");
+                    Some(read_password()?)
+                }
+                false => None,
+            };
+            rotate(
+                &old_password,
+                &new_password,
+                &args[2],
+                recovery.as_deref().map(|s| s.as_str()),
+            )?;
+        }
+        "encrypt" => {
+            if args.len() < 4 {
+                usage(&args[0]);
+                std::process::exit(1);
+            }
+            let pcrs = match args.get(4) {
+                Some(spec) if !spec.starts_with("--") => parse_pcrs(spec)?,
+                _ => Vec::new(),
+            };
+            envelope::encrypt_file(&args[2], &args[3], &pcrs)?;
+        }
+        "decrypt" => {
+            if args.len() < 4 {
+                usage(&args[0]);
+                std::process::exit(1);
+            }
+            envelope::decrypt_file(&args[2], &args[3])?;
+        }
         other => {
             eprintln!("[!] Unknown action: '{}'", other);
             usage(&args[0]);
@@ -2,19 +2,28 @@ use std::convert::TryFrom;
 use tss_esapi::{
     Context,
     TctiNameConf,
+    constants::{CapabilityType, PropertyTag, SessionType},
+    handles::AuthHandle,
+    response_code::Tss2ResponseCodeKind,
+    structures::CapabilityData,
     interface_types::{
         algorithm::{HashingAlgorithm, PublicAlgorithm},
         key_bits::RsaKeyBits,
         resource_handles::Hierarchy,
+        session_handles::PolicySession,
     },
     structures::{
         Digest,
         Auth,
+        PcrSelectionList,
+        PcrSelectionListBuilder,
+        PcrSlot,
         PublicBuilder,
         SensitiveData,
         PublicRsaParametersBuilder,
         RsaExponent,
         RsaScheme,
+        SymmetricDefinition,
         SymmetricDefinitionObject,
         Private,
         Public,
@@ -23,7 +32,10 @@ use tss_esapi::{
     traits::{UnMarshall},
     tcti_ldr::DeviceConfig,
     attributes::ObjectAttributesBuilder,
+    Error as TpmError,
+    WrapperErrorKind,
 };
+use zeroize::Zeroizing;
 
 /// Builds a storage-parent public template: RSA-2048, AES-128-CFB, SHA-256.
 /// Mirrors: tpm2_createprimary -C o -g sha256
@@ -60,12 +72,21 @@ fn build_primary_template() -> tss_esapi::structures::Public {
 }
 
 /// Public template for a sealed-data object.
-fn build_sealed_template() -> Result<tss_esapi::structures::Public, tss_esapi::Error> {
+///
+/// When `auth_policy` is non-empty the object is bound to that policy digest
+/// (PCR state compounded with the auth value), so `user_with_auth` is cleared
+/// and the PIN can only be presented through a matching policy session. An
+/// empty digest keeps the legacy PIN-only behaviour.
+fn build_sealed_template(
+    auth_policy: Digest,
+) -> Result<tss_esapi::structures::Public, tss_esapi::Error> {
+    let policy_bound = !auth_policy.as_ref().is_empty();
+
     let attrs = ObjectAttributesBuilder::new()
         .with_fixed_tpm(true)
         .with_fixed_parent(true)
         .with_no_da(false) // false = dictionary attack protections APPLY
-        .with_user_with_auth(true)
+        .with_user_with_auth(!policy_bound)
         .build()
         .map_err(|e| {
             eprintln!("[!] sealed attrs failed: {:?}", e);
@@ -76,7 +97,7 @@ fn build_sealed_template() -> Result<tss_esapi::structures::Public, tss_esapi::E
         .with_public_algorithm(PublicAlgorithm::KeyedHash)
         .with_name_hashing_algorithm(HashingAlgorithm::Sha256)
         .with_object_attributes(attrs)
-        .with_auth_policy(Digest::default())
+        .with_auth_policy(auth_policy)
         .with_keyed_hash_parameters(tss_esapi::structures::PublicKeyedHashParameters::new(
             tss_esapi::structures::KeyedHashScheme::Null,
         ))
@@ -88,14 +109,103 @@ fn build_sealed_template() -> Result<tss_esapi::structures::Public, tss_esapi::E
         })
 }
 
-/// Initialises a TPM context and creates a primary storage key under the Owner hierarchy.
-/// Returns both the context (must stay alive) and the primary key handle.
-pub fn create_primary() -> Result<(Context, KeyHandle), tss_esapi::Error> {
+/// Builds a SHA-256-bank `PcrSelectionList` from raw PCR indices (e.g. 0,2,4,7).
+/// Mirrors the bank/slot arguments of `tpm2_createpolicy --policy-pcr`.
+pub fn pcr_selection_list(indices: &[u8]) -> Result<PcrSelectionList, tss_esapi::Error> {
+    let slots: Vec<PcrSlot> = indices.iter().map(|i| pcr_slot(*i)).collect::<Result<_, _>>()?;
+    PcrSelectionListBuilder::new()
+        .with_selection(HashingAlgorithm::Sha256, &slots)
+        .build()
+}
+
+/// Maps a numeric PCR index onto the corresponding `PcrSlot` variant.
+fn pcr_slot(index: u8) -> Result<PcrSlot, tss_esapi::Error> {
+    Ok(match index {
+        0 => PcrSlot::Slot0,
+        1 => PcrSlot::Slot1,
+        2 => PcrSlot::Slot2,
+        3 => PcrSlot::Slot3,
+        4 => PcrSlot::Slot4,
+        5 => PcrSlot::Slot5,
+        6 => PcrSlot::Slot6,
+        7 => PcrSlot::Slot7,
+        8 => PcrSlot::Slot8,
+        9 => PcrSlot::Slot9,
+        10 => PcrSlot::Slot10,
+        11 => PcrSlot::Slot11,
+        12 => PcrSlot::Slot12,
+        13 => PcrSlot::Slot13,
+        14 => PcrSlot::Slot14,
+        15 => PcrSlot::Slot15,
+        16 => PcrSlot::Slot16,
+        17 => PcrSlot::Slot17,
+        18 => PcrSlot::Slot18,
+        19 => PcrSlot::Slot19,
+        20 => PcrSlot::Slot20,
+        21 => PcrSlot::Slot21,
+        22 => PcrSlot::Slot22,
+        23 => PcrSlot::Slot23,
+        _ => return Err(TpmError::WrapperError(WrapperErrorKind::InvalidParam)),
+    })
+}
+
+/// Starts an auth session of the given type (trial or policy) and returns it as
+/// a `PolicySession`, ready for `policy_*` calls.
+fn start_policy_session(
+    ctx: &mut Context,
+    session_type: SessionType,
+) -> Result<PolicySession, tss_esapi::Error> {
+    let session = ctx
+        .start_auth_session(
+            None,
+            None,
+            None,
+            session_type,
+            SymmetricDefinition::AES_128_CFB,
+            HashingAlgorithm::Sha256,
+        )?
+        .ok_or(TpmError::WrapperError(WrapperErrorKind::WrongValueFromTpm))?;
+    PolicySession::try_from(session)
+}
+
+/// Replays `policy_pcr` (folding in the current PCR digest for `selection`) and
+/// `policy_auth_value` (so the PIN is still required) into `policy_session`.
+fn apply_pcr_policy(
+    ctx: &mut Context,
+    policy_session: PolicySession,
+    selection: &PcrSelectionList,
+) -> Result<(), tss_esapi::Error> {
+    ctx.policy_pcr(policy_session, Digest::default(), selection.clone())?;
+    ctx.policy_auth_value(policy_session)?;
+    Ok(())
+}
+
+/// Computes, via a trial session, the auth-policy digest that binds a sealed
+/// object to the current values of `selection` AND to its auth value (the PIN).
+fn compute_pcr_policy_digest(
+    ctx: &mut Context,
+    selection: &PcrSelectionList,
+) -> Result<Digest, tss_esapi::Error> {
+    let trial = start_policy_session(ctx, SessionType::Trial)?;
+    apply_pcr_policy(ctx, trial, selection)?;
+    let digest = ctx.policy_get_digest(trial)?;
+    ctx.flush_context(trial.into())?;
+    Ok(digest)
+}
+
+/// Opens a TPM context against the resource-manager device (or `$TPM2TOOLS_TCTI`).
+pub fn new_context() -> Result<Context, tss_esapi::Error> {
     let tcti = TctiNameConf::from_environment_variable()
         .unwrap_or_else(|_| {
             TctiNameConf::Device(DeviceConfig::default()) // /dev/tpmrm0
         });
-    let mut ctx = Context::new(tcti)?;
+    Context::new(tcti)
+}
+
+/// Initialises a TPM context and creates a primary storage key under the Owner hierarchy.
+/// Returns both the context (must stay alive) and the primary key handle.
+pub fn create_primary() -> Result<(Context, KeyHandle), tss_esapi::Error> {
+    let mut ctx = new_context()?;
 
     let template = build_primary_template();
     // println!("[+] Template built OK");
@@ -119,15 +229,24 @@ pub fn tpm_random_bytes(ctx: &mut Context, len: usize) -> Result<Vec<u8>, tss_es
 /// Seals `secret` into the TPM under `primary_handle`, protected by `pin` as auth.
 /// Mirrors: tpm2_create -C primary.ctx -g sha256 -i secret.bin -p "hex:..."
 /// Returns (pub_data, priv_data), analogous to obj.pub + obj.priv.
+///
+/// When `pcr_selection` is `Some`, the object is additionally bound to a policy
+/// that folds in the current PCR digest (measured-boot binding): the secret then
+/// only unseals when both the PCR state and the PIN match.
 pub fn seal_secret(
     ctx: &mut Context,
     primary_handle: KeyHandle,
     secret: &[u8],
     pin: &[u8],
+    pcr_selection: Option<&PcrSelectionList>,
 ) -> Result<(Public, Private), tss_esapi::Error> {
     let auth = Auth::try_from(pin)?;
     let sensitive = SensitiveData::try_from(secret)?;
-    let sealed_template = build_sealed_template()?;
+    let auth_policy = match pcr_selection {
+        Some(selection) => compute_pcr_policy_digest(ctx, selection)?,
+        None => Digest::default(),
+    };
+    let sealed_template = build_sealed_template(auth_policy)?;
 
     let (sealed_pub, sealed_priv) =
         ctx.execute_with_nullauth_session(|ctx: &mut Context| {
@@ -149,13 +268,18 @@ pub fn seal_secret(
 /// Unseals the secret from the TPM, authenticating with `pin`.
 /// A wrong PIN burns one of the daily dictionary-lockout attempts.
 /// Mirrors: tpm2_unseal -c sealed.ctx -p "hex:..."
+///
+/// When `pcr_selection` is `Some`, the same `policy_pcr`/`policy_auth_value`
+/// sequence used at seal time is replayed into a real policy session, and that
+/// session drives the `unseal`; a PCR mismatch makes the TPM refuse.
 pub fn unseal_secret(
     ctx: &mut Context,
     primary_handle: KeyHandle,
     pub_bytes: &[u8],
     priv_bytes: &[u8],
     pin: &[u8],
-) -> Result<Vec<u8>, tss_esapi::Error> {
+    pcr_selection: Option<&PcrSelectionList>,
+) -> Result<Zeroizing<Vec<u8>>, tss_esapi::Error> {
     let sealed_pub = Public::unmarshall(pub_bytes)?;
     let sealed_priv = Private::try_from(priv_bytes.to_vec())?;
 
@@ -166,10 +290,118 @@ pub fn unseal_secret(
     let auth = Auth::try_from(pin.to_vec())?;
     ctx.tr_set_auth(sealed_handle.into(), auth)?;
 
-    let secret = ctx.execute_with_nullauth_session(|ctx: &mut Context| {
-        ctx.unseal(sealed_handle.into())
-    })?;
+    let secret = match pcr_selection {
+        Some(selection) => {
+            let policy_session = start_policy_session(ctx, SessionType::Policy)?;
+            apply_pcr_policy(ctx, policy_session, selection)?;
+            ctx.execute_with_session(Some(policy_session.into()), |ctx: &mut Context| {
+                ctx.unseal(sealed_handle.into())
+            })?
+        }
+        None => ctx.execute_with_nullauth_session(|ctx: &mut Context| {
+            ctx.unseal(sealed_handle.into())
+        })?,
+    };
 
     println!("[+] Secret unsealed successfully");
-    Ok(secret.to_vec())
+    Ok(Zeroizing::new(secret.to_vec()))
+}
+
+/// Dictionary-attack lockout parameters, as reported by the TPM.
+/// Mirrors: tpm2_getcap properties-variable (TPM_PT_MAX_AUTH_FAIL, ...).
+pub struct LockoutInfo {
+    /// Number of failed authorizations before the TPM enters lockout.
+    pub max_auth_fail: u32,
+    /// Current failed-authorization counter; lockout engages once it reaches
+    /// `max_auth_fail`.
+    pub lockout_counter: u32,
+    /// Seconds after which a single failed-attempt counter is decremented.
+    pub lockout_interval: u32,
+    /// Seconds the lockout hierarchy stays unavailable after a failure.
+    pub lockout_recovery: u32,
+}
+
+impl LockoutInfo {
+    /// Attempts left before the TPM enters lockout, saturating at zero.
+    pub fn attempts_remaining(&self) -> u32 {
+        self.max_auth_fail.saturating_sub(self.lockout_counter)
+    }
+}
+
+/// Reads the four lockout-related capability values from the TPM.
+pub fn read_lockout_info(ctx: &mut Context) -> Result<LockoutInfo, tss_esapi::Error> {
+    Ok(LockoutInfo {
+        max_auth_fail: read_tpm_property(ctx, PropertyTag::MaxAuthFail)?,
+        lockout_counter: read_tpm_property(ctx, PropertyTag::LockoutCounter)?,
+        lockout_interval: read_tpm_property(ctx, PropertyTag::LockoutInterval)?,
+        lockout_recovery: read_tpm_property(ctx, PropertyTag::LockoutRecovery)?,
+    })
+}
+
+/// Reads a single fixed/variable TPM property as a `u32`.
+fn read_tpm_property(ctx: &mut Context, tag: PropertyTag) -> Result<u32, tss_esapi::Error> {
+    let (data, _) = ctx.get_capability(CapabilityType::TpmProperties, tag.into(), 1)?;
+    if let CapabilityData::TpmProperties(properties) = data {
+        for property in properties {
+            if property.property() == tag {
+                return Ok(property.value());
+            }
+        }
+    }
+    Err(TpmError::WrapperError(WrapperErrorKind::WrongValueFromTpm))
+}
+
+/// Returns `true` if `err` is a TPM auth-failure or lockout response, i.e. a
+/// wrong PIN that burns an attempt or an already-engaged lockout.
+pub fn is_auth_or_lockout_error(err: &tss_esapi::Error) -> bool {
+    matches!(
+        err,
+        TpmError::Tss2Error(rc)
+            if matches!(
+                rc.kind(),
+                Some(Tss2ResponseCodeKind::Lockout)
+                    | Some(Tss2ResponseCodeKind::AuthFail)
+                    | Some(Tss2ResponseCodeKind::BadAuth)
+            )
+    )
+}
+
+/// If `err` is an auth-failure/lockout response, prints the TPM's lockout policy
+/// so the user understands how many attempts remain and when the counter resets,
+/// rather than seeing an opaque failure.
+pub fn report_lockout_on_error(ctx: &mut Context, err: &tss_esapi::Error) {
+    if !is_auth_or_lockout_error(err) {
+        return;
+    }
+    match read_lockout_info(ctx) {
+        Ok(info) => {
+            eprintln!("[!] TPM authorization failed (wrong PIN or active lockout).");
+            eprintln!(
+                "    {} of {} attempts remain before lockout; it recovers one",
+                info.attempts_remaining(),
+                info.max_auth_fail
+            );
+            eprintln!(
+                "    attempt every {}s; a failed lockout authorization blocks the lockout hierarchy for {}s.",
+                info.lockout_interval, info.lockout_recovery
+            );
+            eprintln!(
+                "    Run 'reset-lockout' to clear the counter now (needs an empty lockout authorization)."
+            );
+        }
+        Err(e) => eprintln!("[!] Could not read TPM lockout state: {:?}", e),
+    }
+}
+
+/// Clears the TPM's dictionary-attack failure counter, authorizing against the
+/// lockout hierarchy with an empty authorization value; no other value can be
+/// supplied, so this fails when a lockout password is set.
+/// Mirrors: tpm2_dictionarylockout --clear-lockout.
+pub fn reset_lockout() -> Result<(), tss_esapi::Error> {
+    let mut ctx = new_context()?;
+    ctx.execute_with_nullauth_session(|ctx: &mut Context| {
+        ctx.dictionary_attack_lock_reset(AuthHandle::Lockout)
+    })?;
+    println!("[+] TPM dictionary-attack lockout counter reset");
+    Ok(())
 }
\ No newline at end of file